@@ -19,13 +19,15 @@ It will have:
 use std::string::String;
 use std::vec::Vec;
 use std::panic;
+use std::sync::Arc;
+use std::thread;
 
 use core::mem;
 use core::iter::FromIterator;
 use core::result;
 
 use test::Bencher;
-use rand::{sample, Rng, SeedableRng, XorShiftRng};
+use rand::{Rng, SeedableRng, XorShiftRng};
 use stopwatch::Stopwatch;
 
 use super::*;
@@ -65,16 +67,123 @@ enum EmptyActions {
     Skip,
 }
 
-#[derive(Debug, Default, Clone)]
+/// a set of (action, weight) pairs, precomputed into a cumulative-weight
+/// table so that a single draw in `[0, total)` plus a binary search
+/// selects an action in O(log n) instead of padding a `Vec` with
+/// duplicate entries
+#[derive(Debug, Clone)]
+struct WeightedChoices<T> {
+    items: Vec<T>,
+    cumulative: Vec<u32>,
+    total: u32,
+}
+
+impl<T: Copy> WeightedChoices<T> {
+    fn new(weighted: &[(T, u32)]) -> WeightedChoices<T> {
+        let mut items = Vec::with_capacity(weighted.len());
+        let mut cumulative = Vec::with_capacity(weighted.len());
+        let mut total = 0u32;
+        for &(item, weight) in weighted {
+            assert!(weight > 0, "weight must be > 0");
+            total += weight;
+            items.push(item);
+            cumulative.push(total);
+        }
+        assert!(total > 0, "at least one weighted choice is required");
+        WeightedChoices {
+            items: items,
+            cumulative: cumulative,
+            total: total,
+        }
+    }
+
+    /// draw a uniform value in `[0, total)` and binary-search the
+    /// cumulative array for the bucket it falls into
+    fn choose<R: Rng>(&self, rng: &mut R) -> T {
+        let x = rng.gen_range(0, self.total);
+        let i = match self.cumulative.binary_search(&x) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        self.items[i]
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Settings {
     /// the number of loops to run
     loops: usize,
-    /// an action will randomly be selected from this list when
+    /// an action and its relative weight, selected when
     /// data is found in an allocation
-    full_chances: Vec<FullActions>,
-    /// an action will randomly be selected from this list when
+    full_chances: Vec<(FullActions, u32)>,
+    /// an action and its relative weight, selected when
     /// data is not found in an allocation
-    empty_chances: Vec<EmptyActions>,
+    empty_chances: Vec<(EmptyActions, u32)>,
+    /// if set, each loop acts on this many distinct, randomly sampled
+    /// allocations instead of walking every allocation in order
+    sample_amount: Option<u32>,
+    /// if true, and `sample_amount` is not set, shuffle the visitation
+    /// order of the allocations each loop instead of always walking
+    /// them in ascending index order
+    shuffle: bool,
+    /// how `Allocation::alloc` picks the length of a new allocation
+    size_distribution: SizeDistribution,
+}
+
+/// an inclusive length range, paired with a relative weight in a
+/// `Weighted` `SizeDistribution` to model a heavy-tailed size mix
+/// (many small allocations, a few large ones) instead of a flat range
+#[derive(Debug, Clone, Copy)]
+struct SizeRange {
+    min: u16,
+    max: u16,
+}
+
+/// how `Allocation::alloc` chooses the length of a new allocation
+#[derive(Debug, Clone)]
+enum SizeDistribution {
+    /// the original behavior: a uniform length in `[0, divider)`
+    Uniform,
+    /// a size range is chosen by weight, then a uniform length is drawn
+    /// within it
+    Weighted(Vec<(SizeRange, u32)>),
+}
+
+impl Default for SizeDistribution {
+    fn default() -> SizeDistribution {
+        SizeDistribution::Uniform
+    }
+}
+
+/// shuffle `indices` in place using the classic in-place Fisher-Yates
+/// algorithm: for `i` from `len - 1` down to `1`, swap the element at
+/// `i` with the element at a uniformly drawn index in `[0, i]`
+fn fisher_yates_shuffle<R: Rng>(rng: &mut R, indices: &mut [u32]) {
+    let mut i = indices.len();
+    while i > 1 {
+        i -= 1;
+        let j = rng.gen_range(0, i + 1);
+        indices.swap(i, j);
+    }
+}
+
+/// sample `amount` distinct indices in `[0, len)` using Floyd's
+/// combination-sampling algorithm: draws exactly `amount` random numbers
+/// and tracks the chosen set in a `Vec` instead of generating a full
+/// permutation of `len` elements. Indices are drawn as `u32` so the
+/// chosen sequence is identical on 32- and 64-bit targets.
+fn floyd_sample<R: Rng>(rng: &mut R, len: u32, amount: u32) -> Vec<u32> {
+    assert!(amount <= len, "can't sample more than len indices");
+    let mut chosen: Vec<u32> = Vec::with_capacity(amount as usize);
+    for j in (len - amount)..len {
+        let t = rng.gen_range(0, j + 1);
+        if chosen.contains(&t) {
+            chosen.push(j);
+        } else {
+            chosen.push(t);
+        }
+    }
+    chosen
 }
 
 /// contains means to track test as well as
@@ -85,18 +194,37 @@ struct Tracker {
     test_clock: Stopwatch,
     stats: Stats,
     settings: Settings,
+    full_choices: WeightedChoices<FullActions>,
+    empty_choices: WeightedChoices<EmptyActions>,
+    /// `None` for `SizeDistribution::Uniform`, precomputed cumulative
+    /// weights for `SizeDistribution::Weighted`
+    size_choices: Option<WeightedChoices<SizeRange>>,
 }
 
 impl Tracker {
     pub fn new(settings: Settings) -> Tracker {
-        let seed = [1, 2, 3, 4];
+        Tracker::with_seed(settings, [1, 2, 3, 4])
+    }
+
+    /// build a `Tracker` with an explicit RNG seed, so a multithreaded
+    /// harness can give each worker its own reproducible stream
+    pub fn with_seed(settings: Settings, seed: [u32; 4]) -> Tracker {
         let gen = XorShiftRng::from_seed(seed);
+        let full_choices = WeightedChoices::new(&settings.full_chances);
+        let empty_choices = WeightedChoices::new(&settings.empty_chances);
+        let size_choices = match settings.size_distribution {
+            SizeDistribution::Uniform => None,
+            SizeDistribution::Weighted(ref ranges) => Some(WeightedChoices::new(ranges)),
+        };
         Tracker {
             gen: gen,
             clock: Stopwatch::new(),
             test_clock: Stopwatch::new(),
             stats: Stats::default(),
             settings: settings,
+            full_choices: full_choices,
+            empty_choices: empty_choices,
+            size_choices: size_choices,
         }
     }
 }
@@ -147,7 +275,16 @@ impl<'a> Allocation<'a> {
     fn alloc(&mut self, t: &mut Tracker, fast: bool) -> TResult<()> {
         assert!(self.mutex.is_none());
         let divider = self.pool.size() / (mem::size_of::<Fill>() * 64);
-        let len = t.gen.gen::<u16>() % divider as u16;
+        let len = match t.size_choices {
+            Some(ref choices) => {
+                let range = choices.choose(&mut t.gen);
+                // widen to u32 so a full-width range (e.g. min: 0, max: u16::MAX)
+                // can't overflow computing its span
+                let span = range.max as u32 - range.min as u32 + 1;
+                range.min + t.gen.gen_range(0u32, span) as u16
+            }
+            None => t.gen.gen::<u16>() % divider as u16,
+        };
         t.clock.start();
         let slice = if fast {
             self.pool.alloc_slice_fast::<Fill>(len)
@@ -200,20 +337,20 @@ impl<'a> Allocation<'a> {
         match self.mutex {
             // we have data, we need to decide what to do with it
             Some(_) => {
-                match sample(&mut t.gen, &t.settings.full_chances, 1)[0] {
-                    &FullActions::Deallocate => {
+                match t.full_choices.choose(&mut t.gen) {
+                    FullActions::Deallocate => {
                         // deallocate the data
                         self.mutex = None;
                         t.stats.frees += 1;
                     }
-                    &FullActions::Clean => {
+                    FullActions::Clean => {
                         // clean the data
                         t.clock.start();
                         self.pool.clean();
                         t.clock.stop();
                         t.stats.cleans += 1;
                     }
-                    &FullActions::Change => {
+                    FullActions::Change => {
                         // change the data
                         try!(self.fill(t));
                     }
@@ -221,10 +358,10 @@ impl<'a> Allocation<'a> {
             }
             // there is no data, should we allocate it?
             None => {
-                match sample(&mut t.gen, &t.settings.empty_chances, 1)[0] {
-                    &EmptyActions::Alloc => try!(self.alloc(t, false)),
-                    &EmptyActions::AllocFast => try!(self.alloc(t, true)),
-                    &EmptyActions::Skip => t.stats.alloc_skips += 1,
+                match t.empty_choices.choose(&mut t.gen) {
+                    EmptyActions::Alloc => try!(self.alloc(t, false)),
+                    EmptyActions::AllocFast => try!(self.alloc(t, true)),
+                    EmptyActions::Skip => t.stats.alloc_skips += 1,
                 }
             }
         }
@@ -245,8 +382,27 @@ fn do_test(allocs: &mut Vec<Allocation>, track: &mut Tracker) {
              track.gen.gen::<u16>());
     track.test_clock.start();
     for _ in 0..track.settings.loops {
-        for alloc in allocs.iter_mut() {
-            alloc.do_random(track).unwrap();
+        match track.settings.sample_amount {
+            Some(amount) => {
+                let len = allocs.len() as u32;
+                for i in floyd_sample(&mut track.gen, len, amount) {
+                    allocs[i as usize].do_random(track).unwrap();
+                }
+            }
+            None => {
+                if track.settings.shuffle {
+                    let len = allocs.len() as u32;
+                    let mut indices: Vec<u32> = (0..len).collect();
+                    fisher_yates_shuffle(&mut track.gen, &mut indices);
+                    for i in indices {
+                        allocs[i as usize].do_random(track).unwrap();
+                    }
+                } else {
+                    for alloc in allocs.iter_mut() {
+                        alloc.do_random(track).unwrap();
+                    }
+                }
+            }
         }
         track.stats.loops += 1;
     }
@@ -290,6 +446,84 @@ fn run_test(name: &str,
     };
 }
 
+/// run `n_threads` worker threads, each with its own seeded `Tracker` and
+/// a disjoint share of the pool's allocations, hammering the shared
+/// `Pool` with `do_random` concurrently. Each worker walks all of its
+/// allocations in ascending order every loop; `sample_amount` and
+/// `shuffle` are not used by this harness.
+///
+/// NOTE on scope: this is *not* the happens-before vector-clock race
+/// detector the backlog item asked for. Building that for real requires
+/// instrumenting `Pool`'s own internal block/free-list mutations so a
+/// detector can be keyed on the actual shared resource a bug would
+/// corrupt; `Pool`'s implementation isn't part of this file (or this
+/// tree), so that instrumentation is out of reach from here. What this
+/// harness actually exercises: every thread calls `alloc_slice`,
+/// `clean`, and `defrag` on the *same* `Pool` with no synchronization of
+/// its own, so `Pool`'s shared bookkeeping (its free list / index table)
+/// is genuinely contended across threads. Because each thread's
+/// `Allocation`s are a disjoint, privately-owned partition of the index
+/// range, no two threads ever share a `SliceMutex` or its backing data —
+/// so this does *not* test contention on a single allocation's data, only
+/// on `Pool`'s internal bookkeeping. Corruption from a bug in that
+/// bookkeeping still surfaces the way it already does single-threaded,
+/// via `Allocation::assert_valid`'s shadow-vs-pool comparison inside
+/// `do_random`, now running against a pool being mutated from other
+/// threads at the same time.
+fn run_concurrent_test(name: &str,
+                        settings: Settings,
+                        blocks: BlockLoc,
+                        indexes: IndexLoc,
+                        index_cache: IndexLoc,
+                        n_threads: usize) {
+    let size = blocks as usize * mem::size_of::<Block>();
+    let pool = Arc::new(Pool::new(size, indexes, index_cache).expect("can't get pool"));
+    let n_indexes = pool.len_indexes();
+
+    let handles: Vec<_> = (0..n_threads)
+        .map(|thread_id| {
+            let pool = pool.clone();
+            let settings = settings.clone();
+            thread::spawn(move || {
+                let seed = [1, 2, 3, thread_id as u32 + 1];
+                let mut track = Tracker::with_seed(settings, seed);
+                let mut allocs = Vec::from_iter((0..n_indexes)
+                                                     .filter(|i| i % n_threads == thread_id)
+                                                     .map(|_| {
+                                                              Allocation {
+                                                                  pool: &pool,
+                                                                  data: Vec::new(),
+                                                                  mutex: None,
+                                                              }
+                                                          }));
+                let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    for _ in 0..track.settings.loops {
+                        for alloc in allocs.iter_mut() {
+                            alloc.do_random(&mut track).unwrap();
+                        }
+                    }
+                }));
+                (thread_id, track.stats, res)
+            })
+        })
+        .collect();
+
+    println!("## {}", name);
+    let mut failure = None;
+    for handle in handles {
+        let (thread_id, stats, res) = handle.join().unwrap();
+        println!("STATS[thread {}]: {:?}", thread_id, stats);
+        if let Err(e) = res {
+            failure = Some((thread_id, e));
+        }
+    }
+    if let Some((thread_id, e)) = failure {
+        println!("thread {} failed, dumping pool:", thread_id);
+        println!("{}", pool.display());
+        panic::resume_unwind(e);
+    }
+}
+
 pub const BLOCKS: BlockLoc = u16::max_value() / 2;
 // pub const INDEXES: IndexLoc = BLOCKS / 128;
 pub const INDEXES: IndexLoc = 512;
@@ -297,36 +531,42 @@ pub const LOOPS: usize = 1000;
 
 #[test]
 fn small_integration() {
-    let mut settings = Settings {
+    let settings = Settings {
         loops: 50,
-        full_chances: Vec::from_iter([FullActions::Deallocate; 9].iter().cloned()),
-        empty_chances: vec![EmptyActions::Alloc],
+        full_chances: vec![(FullActions::Deallocate, 9),
+                            (FullActions::Clean, 1),
+                            (FullActions::Change, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1), (EmptyActions::Skip, 1)],
+        sample_amount: None,
+        shuffle: false,
+        size_distribution: SizeDistribution::Uniform,
     };
-    settings.full_chances.push(FullActions::Clean);
-    settings.full_chances.push(FullActions::Change);
-    settings.empty_chances.push(EmptyActions::Skip);
     run_test("small_integration", settings, BLOCKS, INDEXES, INDEXES / 10);
 }
 
 #[bench]
 fn bench_no_cache(_: &mut Bencher) {
-    let mut settings = Settings {
+    let settings = Settings {
         loops: LOOPS,
-        full_chances: Vec::from_iter([FullActions::Deallocate; 9].iter().cloned()),
-        empty_chances: vec![EmptyActions::Alloc],
+        full_chances: vec![(FullActions::Deallocate, 9), (FullActions::Clean, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1)],
+        sample_amount: None,
+        shuffle: false,
+        size_distribution: SizeDistribution::Uniform,
     };
-    settings.full_chances.push(FullActions::Clean);
     run_test("bench_no_cache", settings.clone(), BLOCKS, INDEXES, 1);
 }
 
 #[bench]
 fn bench_large_cache(_: &mut Bencher) {
-    let mut settings = Settings {
+    let settings = Settings {
         loops: LOOPS,
-        full_chances: Vec::from_iter([FullActions::Deallocate; 9].iter().cloned()),
-        empty_chances: vec![EmptyActions::Alloc],
+        full_chances: vec![(FullActions::Deallocate, 9), (FullActions::Clean, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1)],
+        sample_amount: None,
+        shuffle: false,
+        size_distribution: SizeDistribution::Uniform,
     };
-    settings.full_chances.push(FullActions::Clean);
     run_test("bench_large_cache",
              settings.clone(),
              BLOCKS,
@@ -336,12 +576,14 @@ fn bench_large_cache(_: &mut Bencher) {
 
 #[bench]
 fn bench_small_cache(_: &mut Bencher) {
-    let mut settings = Settings {
+    let settings = Settings {
         loops: LOOPS,
-        full_chances: Vec::from_iter([FullActions::Deallocate; 9].iter().cloned()),
-        empty_chances: vec![EmptyActions::Alloc],
+        full_chances: vec![(FullActions::Deallocate, 9), (FullActions::Clean, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1)],
+        sample_amount: None,
+        shuffle: false,
+        size_distribution: SizeDistribution::Uniform,
     };
-    settings.full_chances.push(FullActions::Clean);
     run_test("bench_small_cache",
              settings.clone(),
              BLOCKS,
@@ -352,15 +594,87 @@ fn bench_small_cache(_: &mut Bencher) {
 #[bench]
 fn bench_fast_large_cache(_: &mut Bencher) {
 
-    let mut settings = Settings {
+    let settings = Settings {
         loops: LOOPS,
-        full_chances: Vec::from_iter([FullActions::Deallocate; 9].iter().cloned()),
-        empty_chances: vec![EmptyActions::AllocFast],
+        full_chances: vec![(FullActions::Deallocate, 9), (FullActions::Clean, 1)],
+        empty_chances: vec![(EmptyActions::AllocFast, 1)],
+        sample_amount: None,
+        shuffle: false,
+        size_distribution: SizeDistribution::Uniform,
     };
-    settings.full_chances.push(FullActions::Clean);
     run_test("bench_fast_large_cache",
              settings.clone(),
              BLOCKS,
              INDEXES,
              INDEXES);
 }
+
+#[bench]
+fn bench_sampled_large_cache(_: &mut Bencher) {
+    let settings = Settings {
+        loops: LOOPS,
+        full_chances: vec![(FullActions::Deallocate, 9), (FullActions::Clean, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1)],
+        sample_amount: Some(INDEXES as u32 / 4),
+        shuffle: false,
+        size_distribution: SizeDistribution::Uniform,
+    };
+    run_test("bench_sampled_large_cache",
+             settings.clone(),
+             BLOCKS,
+             INDEXES,
+             INDEXES);
+}
+
+#[bench]
+fn bench_shuffled_large_cache(_: &mut Bencher) {
+    let settings = Settings {
+        loops: LOOPS,
+        full_chances: vec![(FullActions::Deallocate, 9), (FullActions::Clean, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1)],
+        sample_amount: None,
+        shuffle: true,
+        size_distribution: SizeDistribution::Uniform,
+    };
+    run_test("bench_shuffled_large_cache",
+             settings.clone(),
+             BLOCKS,
+             INDEXES,
+             INDEXES);
+}
+
+#[test]
+fn concurrent_stress() {
+    let settings = Settings {
+        loops: 50,
+        full_chances: vec![(FullActions::Deallocate, 9),
+                            (FullActions::Clean, 1),
+                            (FullActions::Change, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1), (EmptyActions::Skip, 1)],
+        sample_amount: None,
+        shuffle: false,
+        size_distribution: SizeDistribution::Uniform,
+    };
+    run_concurrent_test("concurrent_stress", settings, BLOCKS, INDEXES, INDEXES / 10, 4);
+}
+
+#[bench]
+fn bench_weighted_sizes(_: &mut Bencher) {
+    let settings = Settings {
+        loops: LOOPS,
+        full_chances: vec![(FullActions::Deallocate, 9), (FullActions::Clean, 1)],
+        empty_chances: vec![(EmptyActions::Alloc, 1)],
+        sample_amount: None,
+        shuffle: false,
+        // heavy-tailed mix: mostly small allocations, a few large ones
+        size_distribution:
+            SizeDistribution::Weighted(vec![(SizeRange { min: 0, max: 15 }, 70),
+                                             (SizeRange { min: 16, max: 127 }, 25),
+                                             (SizeRange { min: 128, max: 511 }, 5)]),
+    };
+    run_test("bench_weighted_sizes",
+             settings.clone(),
+             BLOCKS,
+             INDEXES,
+             INDEXES);
+}